@@ -0,0 +1,963 @@
+// Copyright 2020, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::{
+    connection_manager::{AsyncConnectionManagerRequester, ConnectionManagerError},
+    message::{InboundMessage, MessageExt, MessageFlags, MessagePriority, MessageTag, OutboundMessage},
+    peer_manager::{AsyncPeerManager, NodeId, NodeIdentity},
+    proto::envelope::Envelope,
+    protocol::{ProtocolEvent, ProtocolId, ProtocolNotification},
+    types::CommsSubstream,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{channel::mpsc, stream::Stream, SinkExt, StreamExt};
+use prost::Message;
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tari_shutdown::ShutdownSignal;
+use tokio::{
+    runtime,
+    sync::{broadcast, oneshot},
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// The unique protocol identifier used to negotiate the messaging protocol on a substream.
+pub static MESSAGING_PROTOCOL: ProtocolId = ProtocolId::from_static(b"t/msg/0.1");
+
+/// A default length of time for a [MessagingRequest::SendRequest] to wait for a reply before failing with
+/// [RpcError::Timeout]. It is not applied automatically: every `SendRequest` carries its own `timeout`.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A default length of time for a message with `MessageFlags::ACK_REQUESTED` set to wait for the remote's ack before
+/// failing with [SendFailReason::AckTimeout]. It is not applied automatically: [MessagingProtocol::new] always takes
+/// the ack timeout as an argument.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long an incoming stream-request substream is kept in `pending_stream_substreams` waiting for
+/// [MessagingRequest::AttachStream] before it is dropped and closed. Bounds the memory and connection cost of
+/// streams the application declines, or simply never gets around to attaching.
+const PENDING_STREAM_ATTACH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wire marker prepended to every frame on a streaming-response substream (these carry a raw `Bytes` payload rather
+/// than a full [Envelope], so there is no `is_stream_end`-style field to set). Distinguishes a genuine, possibly
+/// zero-length, data chunk from the end of the stream, since an empty payload is itself a legitimate chunk (e.g. a
+/// block-sync reply with no data for a given height) and can no longer double as the end marker.
+const STREAM_FRAME_DATA: u8 = 0;
+/// See [STREAM_FRAME_DATA]. Sent alone (with no payload) as the final frame of a streaming-response substream.
+const STREAM_FRAME_END: u8 = 1;
+
+/// Requests that can be sent to a running [MessagingProtocol].
+pub enum MessagingRequest {
+    /// Send a message and forget about it. The outcome is only observable via [MessagingEvent].
+    SendMessage(OutboundMessage),
+    /// Send a message and await a single correlated reply on `reply`, failing with [RpcError::Timeout] if no reply
+    /// arrives within `timeout`. `out_msg.retry_policy` must be `None`: a request/reply exchange can only be
+    /// attempted once, since retrying it would require keeping `reply` alive across redials, so an attached policy
+    /// is rejected immediately with [SendFailReason::RetryPolicyUnsupported] rather than silently ignored.
+    SendRequest {
+        out_msg: OutboundMessage,
+        timeout: Duration,
+        reply: oneshot::Sender<Result<InboundMessage, RpcError>>,
+    },
+    /// Open a dedicated, long-lived substream to the destination peer and forward every reply frame received on it
+    /// to `responses` until the remote sends an end-of-stream marker or the substream errors. `responses` being a
+    /// bounded channel provides back-pressure through to the substream's yamux receive window.
+    OpenStream {
+        out_msg: OutboundMessage,
+        responses: mpsc::Sender<Bytes>,
+    },
+    /// Attach a stream of reply frames to a previously-notified [MessagingEvent::StreamRequestReceived], writing
+    /// each frame from `responses` to the requester followed by an end-of-stream marker once it closes. `node_id`
+    /// must be the peer carried by that event; stream-open tags are only unique per-peer, not globally.
+    AttachStream {
+        node_id: NodeId,
+        tag: MessageTag,
+        responses: mpsc::Receiver<Bytes>,
+    },
+}
+
+/// Reasons an outbound [OutboundMessage] could not be delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendFailReason {
+    /// The peer could not be dialed.
+    PeerDialFailed,
+    /// Writing the message to the peer's substream failed.
+    SubstreamSendFailed,
+    /// A [RetryPolicy] was attached to the message but every attempt was exhausted without success.
+    RetriesExhausted,
+    /// A [RetryPolicy] was attached to the message but the peer's retry buffer was already full.
+    QueueFull,
+    /// The message had `MessageFlags::ACK_REQUESTED` set but no ack was received within the configured ack timeout.
+    AckTimeout,
+    /// A [RetryPolicy] was attached to a [MessagingRequest::SendRequest], which isn't supported.
+    RetryPolicyUnsupported,
+}
+
+/// An opt-in retry policy for an [OutboundMessage] that could not be delivered immediately. When attached, a
+/// failed send is held in a bounded per-peer buffer and redialled with exponential backoff instead of failing
+/// outright.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// The delay before the first retry attempt.
+    pub base_delay: Duration,
+    /// The maximum delay between retry attempts; the backoff doubles on each failed attempt up to this cap.
+    pub max_delay: Duration,
+    /// The maximum number of retry attempts before giving up with [SendFailReason::RetriesExhausted].
+    pub max_attempts: u32,
+}
+
+struct QueuedRetry {
+    out_msg: OutboundMessage,
+    policy: RetryPolicy,
+    attempt: u32,
+}
+
+/// A single outbound frame queued to a peer/tier's dedicated send worker, with a reply channel for the write's
+/// outcome.
+struct SendJob {
+    frame: Bytes,
+    reply: oneshot::Sender<Result<(), SendFailReason>>,
+}
+
+/// What to do once a [MessagingProtocol::dispatch_send]ed message resolves, carried on [SendOutcome] so the actor
+/// can finish the bookkeeping that differs by call site without itself blocking on the send.
+enum SendCompletion {
+    /// [MessagingRequest::SendMessage]: nothing further to do beyond the usual event.
+    Fire,
+    /// [MessagingRequest::SendRequest]: register the reply channel and arm its timeout on success.
+    Request {
+        timeout: Duration,
+        reply: oneshot::Sender<Result<InboundMessage, RpcError>>,
+    },
+    /// A retry-queue attempt: re-queue with the backoff advanced, or give up, on failure.
+    Retry { attempt: u32 },
+}
+
+/// The result of a [MessagingProtocol::dispatch_send]ed message, reported back to the actor once the relevant tier
+/// worker(s) resolve.
+struct SendOutcome {
+    tag: MessageTag,
+    node_id: NodeId,
+    ack_copy: Option<OutboundMessage>,
+    completion: SendCompletion,
+    result: Result<MessagePriority, (OutboundMessage, SendFailReason)>,
+}
+
+/// Reasons a [MessagingRequest::SendRequest] did not resolve with a reply.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// No reply was received within the request's timeout.
+    Timeout,
+    /// The request could not be sent at all.
+    SendFailed(SendFailReason),
+}
+
+/// Events emitted by a running [MessagingProtocol] on the shared broadcast channel.
+#[derive(Debug, Clone)]
+pub enum MessagingEvent {
+    /// An inbound message was received and handed off on the inbound message channel.
+    MessageReceived(Box<NodeId>, MessageTag),
+    /// An outbound message was written to the destination peer's substream, on the given tier.
+    MessageSent(MessageTag, MessagePriority),
+    /// An outbound message could not be delivered.
+    SendMessageFailed(OutboundMessage, SendFailReason),
+    /// An outbound message with a [RetryPolicy] could not be delivered immediately and was placed in the per-peer
+    /// retry buffer instead of failing.
+    SendMessageQueued(MessageTag),
+    /// The remote peer acked a message sent with `MessageFlags::ACK_REQUESTED` set, confirming it was actually
+    /// received rather than merely handed to the local substream.
+    MessageAcknowledged(MessageTag),
+    /// A peer opened a stream addressed to us, carrying the initial request body. The application should reply
+    /// with [MessagingRequest::AttachStream] using this node id and tag to emit frames back, or simply drop the
+    /// tag to decline. An undeclined, unattached stream is automatically dropped and closed after
+    /// `PENDING_STREAM_ATTACH_TIMEOUT`, emitting [MessagingEvent::StreamClosed].
+    StreamRequestReceived(Box<NodeId>, MessageTag, Bytes),
+    /// A stream (either side) ended, either because the remote sent an end-of-stream marker or the substream
+    /// errored.
+    StreamClosed(MessageTag),
+}
+
+pub type MessagingEventSender = broadcast::Sender<Arc<MessagingEvent>>;
+
+/// A `Stream` adapter over a [broadcast::Receiver] of [MessagingEvent]s.
+pub struct MessagingEventReceiver(broadcast::Receiver<Arc<MessagingEvent>>);
+
+impl From<broadcast::Receiver<Arc<MessagingEvent>>> for MessagingEventReceiver {
+    fn from(rx: broadcast::Receiver<Arc<MessagingEvent>>) -> Self {
+        Self(rx)
+    }
+}
+
+impl Stream for MessagingEventReceiver {
+    type Item = Result<Arc<MessagingEvent>, broadcast::error::RecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(Some)
+    }
+}
+
+/// A framed, length-delimited codec over a comms substream used to exchange [Envelope]s.
+pub type MessagingFramedSubstream = Framed<CommsSubstream, LengthDelimitedCodec>;
+
+/// The messaging protocol actor.
+///
+/// Accepts new inbound substreams negotiated for [MESSAGING_PROTOCOL] and forwards decoded [Envelope]s to the
+/// inbound message channel, while servicing [MessagingRequest]s to send outbound messages (and, for
+/// [MessagingRequest::SendRequest], correlate a single reply) via an on-demand, dedicated send worker per
+/// peer/priority tier. Dispatching a send only resolves the relevant worker(s) and hands the write off to a
+/// detached task, so the actor's own request loop is never blocked waiting on a substream write.
+pub struct MessagingProtocol {
+    executor: runtime::Handle,
+    connection_manager: AsyncConnectionManagerRequester,
+    peer_manager: AsyncPeerManager,
+    node_identity: Arc<NodeIdentity>,
+    proto_notification_rx: mpsc::Receiver<ProtocolNotification<CommsSubstream>>,
+    request_rx: mpsc::Receiver<MessagingRequest>,
+    event_tx: MessagingEventSender,
+    inbound_message_tx: mpsc::Sender<InboundMessage>,
+    inbound_envelope_tx: mpsc::Sender<(NodeId, Envelope)>,
+    inbound_envelope_rx: mpsc::Receiver<(NodeId, Envelope)>,
+    inbound_stream_tx: mpsc::Sender<(NodeId, MessageTag, Bytes, MessagingFramedSubstream)>,
+    inbound_stream_rx: mpsc::Receiver<(NodeId, MessageTag, Bytes, MessagingFramedSubstream)>,
+    /// Dedicated send workers for `MessagePriority::Normal` traffic, each exclusively owning one peer's substream
+    /// and servicing its own job queue, entirely independent of `high_priority_send_workers` (see that field).
+    normal_send_workers: HashMap<NodeId, mpsc::Sender<SendJob>>,
+    /// Pending [MessagingRequest::SendRequest] replies, keyed by tag with the peer the request was sent to so a
+    /// reply-shaped envelope from an unrelated peer with a colliding tag cannot be mistaken for the real reply.
+    pending_requests: HashMap<MessageTag, (NodeId, oneshot::Sender<Result<InboundMessage, RpcError>>)>,
+    /// Substreams opened to us via the streaming-response subprotocol, awaiting [MessagingRequest::AttachStream].
+    /// Keyed by `(NodeId, MessageTag)` since the tag is only chosen uniquely by each remote peer, not globally.
+    pending_stream_substreams: HashMap<(NodeId, MessageTag), MessagingFramedSubstream>,
+    stream_attach_timeout_tx: mpsc::Sender<(NodeId, MessageTag)>,
+    stream_attach_timeout_rx: mpsc::Receiver<(NodeId, MessageTag)>,
+    /// Dedicated send workers for `MessagePriority::High` traffic, kept entirely separate from
+    /// `normal_send_workers` (its own task and job queue per peer) so a backlog or slow write on the normal tier
+    /// can never head-of-line block a high-priority send to the same peer.
+    high_priority_send_workers: HashMap<NodeId, mpsc::Sender<SendJob>>,
+    request_timeout_tx: mpsc::Sender<MessageTag>,
+    request_timeout_rx: mpsc::Receiver<MessageTag>,
+    /// Per-peer store-and-forward buffers for messages with a [RetryPolicy] attached, bounded by `retry_capacity`.
+    retry_queues: HashMap<NodeId, VecDeque<QueuedRetry>>,
+    retry_capacity: usize,
+    retry_tick_tx: mpsc::Sender<NodeId>,
+    retry_tick_rx: mpsc::Receiver<NodeId>,
+    /// Outbound messages sent with `MessageFlags::ACK_REQUESTED`, awaiting the remote's ack.
+    pending_acks: HashMap<MessageTag, OutboundMessage>,
+    ack_timeout_tx: mpsc::Sender<MessageTag>,
+    ack_timeout_rx: mpsc::Receiver<MessageTag>,
+    ack_timeout: Duration,
+    /// Outcomes of sends dispatched to a tier worker, reported back here once the worker resolves them so the
+    /// actor can finish bookkeeping (events, acks, retries, request replies) without itself blocking on the write.
+    send_result_tx: mpsc::Sender<SendOutcome>,
+    send_result_rx: mpsc::Receiver<SendOutcome>,
+    shutdown_signal: ShutdownSignal,
+}
+
+impl MessagingProtocol {
+    pub fn new(
+        executor: runtime::Handle,
+        connection_manager: AsyncConnectionManagerRequester,
+        peer_manager: AsyncPeerManager,
+        node_identity: Arc<NodeIdentity>,
+        proto_notification_rx: mpsc::Receiver<ProtocolNotification<CommsSubstream>>,
+        request_rx: mpsc::Receiver<MessagingRequest>,
+        event_tx: MessagingEventSender,
+        inbound_message_tx: mpsc::Sender<InboundMessage>,
+        retry_queue_capacity: usize,
+        ack_timeout: Duration,
+        shutdown_signal: ShutdownSignal,
+    ) -> Self {
+        let (request_timeout_tx, request_timeout_rx) = mpsc::channel(20);
+        let (inbound_envelope_tx, inbound_envelope_rx) = mpsc::channel(100);
+        let (retry_tick_tx, retry_tick_rx) = mpsc::channel(20);
+        let (inbound_stream_tx, inbound_stream_rx) = mpsc::channel(20);
+        let (ack_timeout_tx, ack_timeout_rx) = mpsc::channel(20);
+        let (stream_attach_timeout_tx, stream_attach_timeout_rx) = mpsc::channel(20);
+        let (send_result_tx, send_result_rx) = mpsc::channel(100);
+        Self {
+            executor,
+            connection_manager,
+            peer_manager,
+            node_identity,
+            proto_notification_rx,
+            request_rx,
+            event_tx,
+            inbound_message_tx,
+            inbound_envelope_tx,
+            inbound_envelope_rx,
+            inbound_stream_tx,
+            inbound_stream_rx,
+            normal_send_workers: HashMap::new(),
+            pending_requests: HashMap::new(),
+            pending_stream_substreams: HashMap::new(),
+            stream_attach_timeout_tx,
+            stream_attach_timeout_rx,
+            high_priority_send_workers: HashMap::new(),
+            request_timeout_tx,
+            request_timeout_rx,
+            retry_queues: HashMap::new(),
+            retry_capacity: retry_queue_capacity,
+            retry_tick_tx,
+            retry_tick_rx,
+            pending_acks: HashMap::new(),
+            ack_timeout_tx,
+            ack_timeout_rx,
+            ack_timeout,
+            send_result_tx,
+            send_result_rx,
+            shutdown_signal,
+        }
+    }
+
+    /// Wrap a raw comms substream in the length-delimited codec used to frame [Envelope]s.
+    pub fn framed(substream: CommsSubstream) -> MessagingFramedSubstream {
+        Framed::new(substream, LengthDelimitedCodec::new())
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                Some(notification) = self.proto_notification_rx.next() => {
+                    self.handle_protocol_notification(notification);
+                },
+
+                Some((node_id, envelope)) = self.inbound_envelope_rx.next() => {
+                    self.handle_inbound_envelope(node_id, envelope).await;
+                },
+
+                Some((node_id, tag, body, framed)) = self.inbound_stream_rx.next() => {
+                    self.pending_stream_substreams.insert((node_id.clone(), tag), framed);
+                    let mut stream_attach_timeout_tx = self.stream_attach_timeout_tx.clone();
+                    let timeout_node_id = node_id.clone();
+                    self.executor.spawn(async move {
+                        time::sleep(PENDING_STREAM_ATTACH_TIMEOUT).await;
+                        let _ = stream_attach_timeout_tx.send((timeout_node_id, tag)).await;
+                    });
+                    let _ = self
+                        .event_tx
+                        .send(Arc::new(MessagingEvent::StreamRequestReceived(Box::new(node_id), tag, body)));
+                },
+
+                Some(request) = self.request_rx.next() => {
+                    self.handle_request(request).await;
+                },
+
+                Some(tag) = self.request_timeout_rx.next() => {
+                    self.handle_request_timeout(tag);
+                },
+
+                Some(node_id) = self.retry_tick_rx.next() => {
+                    self.handle_retry_tick(node_id).await;
+                },
+
+                Some(tag) = self.ack_timeout_rx.next() => {
+                    self.handle_ack_timeout(tag);
+                },
+
+                Some((node_id, tag)) = self.stream_attach_timeout_rx.next() => {
+                    self.handle_stream_attach_timeout(node_id, tag);
+                },
+
+                Some(outcome) = self.send_result_rx.next() => {
+                    self.handle_send_outcome(outcome);
+                },
+
+                _ = self.shutdown_signal.wait() => {
+                    break;
+                },
+            }
+        }
+    }
+
+    fn handle_protocol_notification(&mut self, notification: ProtocolNotification<CommsSubstream>) {
+        match notification.event {
+            ProtocolEvent::NewInboundSubstream(node_id, substream) => {
+                let framed = Self::framed(substream);
+                self.spawn_inbound_handler(*node_id, framed);
+            },
+        }
+    }
+
+    /// Decode frames from a newly opened inbound substream and forward each [Envelope] to the actor loop for
+    /// routing. Decoding happens off the actor task so a slow/malicious peer cannot block other peers' traffic.
+    ///
+    /// If the first frame on the substream has `is_stream_open` set, the substream is instead handed whole to the
+    /// actor loop via `inbound_stream_tx` so the application can attach reply frames to it; regular substreams may
+    /// carry many envelopes and keep looping for the life of the substream.
+    fn spawn_inbound_handler(&self, node_id: NodeId, mut framed: MessagingFramedSubstream) {
+        let mut inbound_envelope_tx = self.inbound_envelope_tx.clone();
+        let mut inbound_stream_tx = self.inbound_stream_tx.clone();
+        self.executor.spawn(async move {
+            while let Some(Ok(bytes)) = framed.next().await {
+                let envelope = match Envelope::decode(bytes.freeze()) {
+                    Ok(envelope) => envelope,
+                    Err(_) => continue,
+                };
+                if envelope.is_stream_open {
+                    let tag = MessageTag::from(envelope.request_id);
+                    let body: Bytes = envelope.body.into();
+                    let _ = inbound_stream_tx.send((node_id, tag, body, framed)).await;
+                    return;
+                }
+                if inbound_envelope_tx.send((node_id.clone(), envelope)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Route a decoded inbound envelope: an ack resolves a pending [MessagingEvent::MessageAcknowledged] and is
+    /// never itself acked; a reply to an in-flight [MessagingRequest::SendRequest] is resolved directly against the
+    /// pending oneshot; everything else is delivered as a regular inbound message, acked back to the sender first
+    /// if it asked for one.
+    async fn handle_inbound_envelope(&mut self, node_id: NodeId, envelope: Envelope) {
+        if envelope.is_ack {
+            self.resolve_pending_ack(&node_id, MessageTag::from(envelope.in_response_to));
+            return;
+        }
+
+        let ack_requested = MessageFlags::from_bits_truncate(envelope.flags).contains(MessageFlags::ACK_REQUESTED);
+        let request_id = envelope.request_id;
+
+        if envelope.in_response_to != 0 {
+            let tag = MessageTag::from(envelope.in_response_to);
+            if let Ok(peer) = self.peer_manager.find_by_node_id(&node_id).await {
+                let in_msg = InboundMessage::new(peer, envelope.body.into());
+                self.resolve_pending_request(tag, &node_id, in_msg);
+            }
+            if ack_requested {
+                self.send_ack(node_id, request_id).await;
+            }
+            return;
+        }
+
+        let peer = match self.peer_manager.find_by_node_id(&node_id).await {
+            Ok(peer) => peer,
+            Err(_) => return,
+        };
+        let in_msg = InboundMessage::new(peer, envelope.body.into());
+        let tag = in_msg.tag;
+        if self.inbound_message_tx.send(in_msg).await.is_ok() {
+            let _ = self
+                .event_tx
+                .send(Arc::new(MessagingEvent::MessageReceived(Box::new(node_id.clone()), tag)));
+            if ack_requested {
+                self.send_ack(node_id, request_id).await;
+            }
+        }
+    }
+
+    /// Send the tiny, un-acked reply frame that fulfils a `MessageFlags::ACK_REQUESTED` request. Best-effort: a
+    /// failure here simply means the sender's own ack timeout sweep will fail the message instead.
+    async fn send_ack(&mut self, node_id: NodeId, in_response_to: u64) {
+        let mut ack = Envelope::construct_signed(
+            self.node_identity.secret_key(),
+            self.node_identity.public_key(),
+            Bytes::new(),
+            MessageFlags::empty(),
+        )
+        .expect("node identity key is always valid");
+        ack.in_response_to = in_response_to;
+        ack.is_ack = true;
+        let frame: Bytes = ack.to_encoded_bytes().expect("envelope always encodes").into();
+        if let Ok(job_tx) = self.get_send_worker(&node_id, MessagePriority::Normal).await {
+            let _ = Self::send_job(job_tx, frame).await;
+        }
+    }
+
+    /// Complete a pending ack wait, emitting [MessagingEvent::MessageAcknowledged]. A miss (e.g. it already timed
+    /// out, or `node_id` is not the peer the original message was sent to) is silently dropped.
+    fn resolve_pending_ack(&mut self, node_id: &NodeId, tag: MessageTag) {
+        let acked_by_expected_peer = self
+            .pending_acks
+            .get(&tag)
+            .map_or(false, |out_msg| &out_msg.peer_node_id == node_id);
+        if acked_by_expected_peer && self.pending_acks.remove(&tag).is_some() {
+            let _ = self.event_tx.send(Arc::new(MessagingEvent::MessageAcknowledged(tag)));
+        }
+    }
+
+    /// Fail a pending ack wait with [SendFailReason::AckTimeout]. A miss (e.g. the ack arrived just before this
+    /// fired) is silently dropped.
+    fn handle_ack_timeout(&mut self, tag: MessageTag) {
+        if let Some(out_msg) = self.pending_acks.remove(&tag) {
+            let _ = self
+                .event_tx
+                .send(Arc::new(MessagingEvent::SendMessageFailed(out_msg, SendFailReason::AckTimeout)));
+        }
+    }
+
+    /// Register `out_msg` as awaiting an ack and arm its timeout, to be called whenever a message with
+    /// `MessageFlags::ACK_REQUESTED` set is successfully handed to a substream.
+    fn await_ack(&mut self, out_msg: OutboundMessage) {
+        let tag = out_msg.tag;
+        self.pending_acks.insert(tag, out_msg);
+        let mut ack_timeout_tx = self.ack_timeout_tx.clone();
+        let ack_timeout = self.ack_timeout;
+        self.executor.spawn(async move {
+            time::sleep(ack_timeout).await;
+            let _ = ack_timeout_tx.send(tag).await;
+        });
+    }
+
+    async fn handle_request(&mut self, request: MessagingRequest) {
+        match request {
+            MessagingRequest::SendMessage(out_msg) => {
+                self.dispatch_send(out_msg, SendCompletion::Fire).await;
+            },
+            MessagingRequest::SendRequest { out_msg, timeout, reply } => {
+                if out_msg.retry_policy.is_some() {
+                    // A RetryPolicy doesn't compose with a one-shot reply channel: a retried attempt would need to
+                    // keep `reply` alive across redials, which the retry queue isn't built to carry. Reject up
+                    // front with a clear reason rather than silently ignoring the policy.
+                    let _ = self.event_tx.send(Arc::new(MessagingEvent::SendMessageFailed(
+                        out_msg,
+                        SendFailReason::RetryPolicyUnsupported,
+                    )));
+                    let _ = reply.send(Err(RpcError::SendFailed(SendFailReason::RetryPolicyUnsupported)));
+                    return;
+                }
+                self.dispatch_send(out_msg, SendCompletion::Request { timeout, reply }).await;
+            },
+            MessagingRequest::OpenStream { out_msg, responses } => {
+                self.open_stream(out_msg, responses).await;
+            },
+            MessagingRequest::AttachStream { node_id, tag, responses } => {
+                self.attach_stream(node_id, tag, responses);
+            },
+        }
+    }
+
+    /// Dial the peer, open a dedicated substream and send the initial stream-open envelope, then spawn a task that
+    /// forwards every reply frame to `responses` until a [STREAM_FRAME_END] marker frame arrives.
+    async fn open_stream(&mut self, out_msg: OutboundMessage, responses: mpsc::Sender<Bytes>) {
+        let tag = out_msg.tag;
+        let priority = out_msg.priority;
+        let node_id = out_msg.peer_node_id.clone();
+        let mut envelope = self.construct_envelope(&out_msg);
+        envelope.is_stream_open = true;
+
+        let connection = match self.connection_manager.dial_peer(node_id).await {
+            Ok(connection) => connection,
+            Err(_) => {
+                let _ = self.event_tx.send(Arc::new(MessagingEvent::SendMessageFailed(
+                    out_msg,
+                    SendFailReason::PeerDialFailed,
+                )));
+                return;
+            },
+        };
+        let substream = match connection.open_substream(&MESSAGING_PROTOCOL).await {
+            Ok(substream) => substream,
+            Err(_) => {
+                let _ = self.event_tx.send(Arc::new(MessagingEvent::SendMessageFailed(
+                    out_msg,
+                    SendFailReason::PeerDialFailed,
+                )));
+                return;
+            },
+        };
+        let mut framed = Self::framed(substream);
+        if framed
+            .send(envelope.to_encoded_bytes().expect("envelope always encodes").into())
+            .await
+            .is_err()
+        {
+            let _ = self.event_tx.send(Arc::new(MessagingEvent::SendMessageFailed(
+                out_msg,
+                SendFailReason::SubstreamSendFailed,
+            )));
+            return;
+        }
+        let _ = self.event_tx.send(Arc::new(MessagingEvent::MessageSent(tag, priority)));
+
+        let event_tx = self.event_tx.clone();
+        let mut responses = responses;
+        self.executor.spawn(async move {
+            while let Some(Ok(mut frame)) = framed.next().await {
+                if frame.is_empty() {
+                    break;
+                }
+                let marker = frame.split_to(1)[0];
+                if marker == STREAM_FRAME_END || responses.send(frame.freeze()).await.is_err() {
+                    break;
+                }
+            }
+            let _ = event_tx.send(Arc::new(MessagingEvent::StreamClosed(tag)));
+        });
+    }
+
+    /// Drain `responses` onto the substream previously registered for `(node_id, tag)` by
+    /// [MessagingEvent::StreamRequestReceived], each chunk prefixed with [STREAM_FRAME_DATA] so it can't be mistaken
+    /// for the [STREAM_FRAME_END] marker frame sent once the channel closes.
+    fn attach_stream(&mut self, node_id: NodeId, tag: MessageTag, mut responses: mpsc::Receiver<Bytes>) {
+        let mut framed = match self.pending_stream_substreams.remove(&(node_id, tag)) {
+            Some(framed) => framed,
+            None => return,
+        };
+        let event_tx = self.event_tx.clone();
+        self.executor.spawn(async move {
+            while let Some(bytes) = responses.next().await {
+                let mut frame = BytesMut::with_capacity(bytes.len() + 1);
+                frame.put_u8(STREAM_FRAME_DATA);
+                frame.extend_from_slice(&bytes);
+                if framed.send(frame.freeze()).await.is_err() {
+                    let _ = event_tx.send(Arc::new(MessagingEvent::StreamClosed(tag)));
+                    return;
+                }
+            }
+            let _ = framed.send(Bytes::from_static(&[STREAM_FRAME_END])).await;
+            let _ = event_tx.send(Arc::new(MessagingEvent::StreamClosed(tag)));
+        });
+    }
+
+    /// Drop (closing) a stream-request substream left unattached for `PENDING_STREAM_ATTACH_TIMEOUT`, mirroring the
+    /// sweep used for `pending_requests`/`pending_acks`. A miss (it was attached, or already closed and swept, in
+    /// the meantime) is silently dropped.
+    fn handle_stream_attach_timeout(&mut self, node_id: NodeId, tag: MessageTag) {
+        if self.pending_stream_substreams.remove(&(node_id, tag)).is_some() {
+            let _ = self.event_tx.send(Arc::new(MessagingEvent::StreamClosed(tag)));
+        }
+    }
+
+    fn handle_request_timeout(&mut self, tag: MessageTag) {
+        if let Some((_, reply)) = self.pending_requests.remove(&tag) {
+            let _ = reply.send(Err(RpcError::Timeout));
+        }
+    }
+
+    /// Complete a pending [MessagingRequest::SendRequest] with a reply that was routed back in via
+    /// `Envelope::in_response_to`. A miss (e.g. the request already timed out, or `node_id` is not the peer the
+    /// request was sent to) is silently dropped.
+    fn resolve_pending_request(&mut self, tag: MessageTag, node_id: &NodeId, in_msg: InboundMessage) {
+        let replied_by_expected_peer = self
+            .pending_requests
+            .get(&tag)
+            .map_or(false, |(expected_node_id, _)| expected_node_id == node_id);
+        if replied_by_expected_peer {
+            if let Some((_, reply)) = self.pending_requests.remove(&tag) {
+                let _ = reply.send(Ok(in_msg));
+            }
+        }
+    }
+
+    /// Handle a failed [MessagingRequest::SendMessage]. Without a [RetryPolicy] this is a terminal failure; with
+    /// one, the message is placed in the peer's retry buffer instead (or fails with
+    /// [SendFailReason::QueueFull] if that buffer is already full).
+    fn handle_send_failure(&mut self, out_msg: OutboundMessage, reason: SendFailReason) {
+        match out_msg.retry_policy {
+            Some(policy) => match self.enqueue_retry(out_msg, policy) {
+                Ok(tag) => {
+                    let _ = self.event_tx.send(Arc::new(MessagingEvent::SendMessageQueued(tag)));
+                },
+                Err(out_msg) => {
+                    let _ = self
+                        .event_tx
+                        .send(Arc::new(MessagingEvent::SendMessageFailed(out_msg, SendFailReason::QueueFull)));
+                },
+            },
+            None => {
+                let _ = self
+                    .event_tx
+                    .send(Arc::new(MessagingEvent::SendMessageFailed(out_msg, reason)));
+            },
+        }
+    }
+
+    /// Push `out_msg` onto its peer's retry buffer, starting the redial timer if it is the only entry. Fails with
+    /// the original message if the peer's buffer is already at `retry_capacity`.
+    fn enqueue_retry(&mut self, out_msg: OutboundMessage, policy: RetryPolicy) -> Result<MessageTag, OutboundMessage> {
+        let node_id = out_msg.peer_node_id.clone();
+        let tag = out_msg.tag;
+        let queue = self.retry_queues.entry(node_id.clone()).or_insert_with(VecDeque::new);
+        if queue.len() >= self.retry_capacity {
+            return Err(out_msg);
+        }
+        let should_schedule = queue.is_empty();
+        queue.push_back(QueuedRetry {
+            out_msg,
+            policy,
+            attempt: 0,
+        });
+        if should_schedule {
+            self.schedule_retry(node_id, policy.base_delay);
+        }
+        Ok(tag)
+    }
+
+    fn schedule_retry(&self, node_id: NodeId, delay: Duration) {
+        let mut retry_tick_tx = self.retry_tick_tx.clone();
+        self.executor.spawn(async move {
+            time::sleep(delay).await;
+            let _ = retry_tick_tx.send(node_id).await;
+        });
+    }
+
+    /// The delay to wait before `entry`'s next attempt: `base_delay` if it has never been tried, otherwise the
+    /// backoff doubled per attempt and capped at `max_delay`, matching the schedule [MessagingProtocol::enqueue_retry]
+    /// and [MessagingProtocol::handle_retry_tick] advance an entry through.
+    fn next_attempt_delay(entry: &QueuedRetry) -> Duration {
+        if entry.attempt == 0 {
+            entry.policy.base_delay
+        } else {
+            entry
+                .policy
+                .base_delay
+                .saturating_mul(2u32.saturating_pow(entry.attempt))
+                .min(entry.policy.max_delay)
+        }
+    }
+
+    /// Pop the next queued message for `node_id` and retry it, reported back through [MessagingProtocol::handle_send_outcome]
+    /// once it resolves, where it is either acknowledged as sent, re-queued with its backoff doubled (capped at
+    /// `max_delay`), or surfaced as [SendFailReason::RetriesExhausted] if `max_attempts` has been reached. Pacing
+    /// the next retry tick off whatever ends up at the front of the queue (rather than the entry processed here)
+    /// also happens there, since a requeue of this very entry can change what that front entry is.
+    async fn handle_retry_tick(&mut self, node_id: NodeId) {
+        let entry = match self.retry_queues.get_mut(&node_id).and_then(VecDeque::pop_front) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let QueuedRetry { out_msg, attempt, .. } = entry;
+        self.dispatch_send(out_msg, SendCompletion::Retry { attempt }).await;
+    }
+
+    /// Get (dialing and opening a substream, then spawning its dedicated send worker, if necessary) the job queue
+    /// for `node_id` on the given tier. Each tier's workers are independent tasks with their own substream and job
+    /// queue, which is what lets a `High` priority send proceed concurrently with a `Normal` tier write already in
+    /// flight on the same peer instead of queuing behind it.
+    async fn get_send_worker(
+        &mut self,
+        node_id: &NodeId,
+        priority: MessagePriority,
+    ) -> Result<mpsc::Sender<SendJob>, SendFailReason> {
+        let workers = match priority {
+            MessagePriority::High => &mut self.high_priority_send_workers,
+            MessagePriority::Normal => &mut self.normal_send_workers,
+        };
+        if let Some(job_tx) = workers.get(node_id) {
+            // A worker that exited after a failed write has dropped its receiver; replace it below.
+            if !job_tx.is_closed() {
+                return Ok(job_tx.clone());
+            }
+        }
+
+        let connection = self
+            .connection_manager
+            .dial_peer(node_id.clone())
+            .await
+            .map_err(|_: ConnectionManagerError| SendFailReason::PeerDialFailed)?;
+        let substream = connection
+            .open_substream(&MESSAGING_PROTOCOL)
+            .await
+            .map_err(|_| SendFailReason::PeerDialFailed)?;
+
+        let (job_tx, job_rx) = mpsc::channel(100);
+        self.executor.spawn(Self::run_send_worker(Self::framed(substream), job_rx));
+        workers.insert(node_id.clone(), job_tx.clone());
+        Ok(job_tx)
+    }
+
+    /// Exclusively own `framed`, writing each queued job's frame to it in turn and reporting the outcome back on
+    /// `job.reply`. Exits (dropping `job_rx`) on the first write failure, since the underlying substream is
+    /// presumed dead; a job that then lands on the now-closed channel fails immediately rather than hanging, and
+    /// `get_send_worker` dials and spawns a fresh worker the next time this peer/tier is needed.
+    async fn run_send_worker(mut framed: MessagingFramedSubstream, mut job_rx: mpsc::Receiver<SendJob>) {
+        while let Some(job) = job_rx.next().await {
+            let result = framed.send(job.frame).await.map_err(|_| SendFailReason::SubstreamSendFailed);
+            let failed = result.is_err();
+            let _ = job.reply.send(result);
+            if failed {
+                break;
+            }
+        }
+    }
+
+    /// Queue `frame` on `job_tx`'s worker and await the write's outcome.
+    async fn send_job(mut job_tx: mpsc::Sender<SendJob>, frame: Bytes) -> Result<(), SendFailReason> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if job_tx.send(SendJob { frame, reply: reply_tx }).await.is_err() {
+            return Err(SendFailReason::SubstreamSendFailed);
+        }
+        reply_rx.await.unwrap_or(Err(SendFailReason::SubstreamSendFailed))
+    }
+
+    /// Resolve (dialing/spawning this peer's tier worker(s) if necessary) and hand `out_msg` off to a detached task
+    /// that performs the actual write(s), reporting the outcome back on `self.send_result_rx` once they resolve.
+    /// This is what lets the actor move on to the next [MessagingRequest] immediately rather than blocking on the
+    /// write, which is what let a backlog of `Normal` tier sends head-of-line block `High` tier traffic before.
+    async fn dispatch_send(&mut self, out_msg: OutboundMessage, completion: SendCompletion) {
+        let tag = out_msg.tag;
+        let node_id = out_msg.peer_node_id.clone();
+        let ack_copy = out_msg
+            .flags
+            .contains(MessageFlags::ACK_REQUESTED)
+            .then(|| out_msg.clone());
+
+        let high_job_tx = if out_msg.priority == MessagePriority::High {
+            self.get_send_worker(&node_id, MessagePriority::High).await.ok()
+        } else {
+            None
+        };
+        let normal_job_tx = self.get_send_worker(&node_id, MessagePriority::Normal).await;
+
+        let envelope = self.construct_envelope(&out_msg);
+        let frame: Bytes = envelope.to_encoded_bytes().expect("envelope always encodes").into();
+        let mut send_result_tx = self.send_result_tx.clone();
+
+        self.executor.spawn(async move {
+            let result = Self::send_via_tiers(high_job_tx, normal_job_tx, frame, out_msg).await;
+            let _ = send_result_tx
+                .send(SendOutcome {
+                    tag,
+                    node_id,
+                    ack_copy,
+                    completion,
+                    result,
+                })
+                .await;
+        });
+    }
+
+    /// Try the reserved `High` worker first if one was resolved, falling back to the `Normal` worker —
+    /// [SendFailReason::SubstreamSendFailed] (or whatever `normal_job_tx` already failed with) is only surfaced
+    /// once both tiers have been tried. Returns the tier the message actually went out on.
+    async fn send_via_tiers(
+        high_job_tx: Option<mpsc::Sender<SendJob>>,
+        normal_job_tx: Result<mpsc::Sender<SendJob>, SendFailReason>,
+        frame: Bytes,
+        out_msg: OutboundMessage,
+    ) -> Result<MessagePriority, (OutboundMessage, SendFailReason)> {
+        if let Some(job_tx) = high_job_tx {
+            if Self::send_job(job_tx, frame.clone()).await.is_ok() {
+                return Ok(MessagePriority::High);
+            }
+            // Reserved worker unavailable or its write failed; fall back to the normal tier below.
+        }
+
+        match normal_job_tx {
+            Ok(job_tx) => match Self::send_job(job_tx, frame).await {
+                Ok(_) => Ok(MessagePriority::Normal),
+                Err(reason) => Err((out_msg, reason)),
+            },
+            Err(reason) => Err((out_msg, reason)),
+        }
+    }
+
+    /// Finish the bookkeeping for a [SendOutcome] reported by a detached [MessagingProtocol::dispatch_send] task:
+    /// emit the usual events, register/arm an ack wait, fulfil a [MessagingRequest::SendRequest] reply, or advance
+    /// a retry-queue entry, matching what [MessagingProtocol::handle_request] and
+    /// [MessagingProtocol::handle_retry_tick] used to do inline before the send itself was moved off the actor.
+    fn handle_send_outcome(&mut self, outcome: SendOutcome) {
+        let SendOutcome {
+            tag,
+            node_id,
+            ack_copy,
+            completion,
+            result,
+        } = outcome;
+
+        match completion {
+            SendCompletion::Fire => match result {
+                Ok(tier) => {
+                    let _ = self.event_tx.send(Arc::new(MessagingEvent::MessageSent(tag, tier)));
+                    if let Some(out_msg) = ack_copy {
+                        self.await_ack(out_msg);
+                    }
+                },
+                Err((out_msg, reason)) => self.handle_send_failure(out_msg, reason),
+            },
+            SendCompletion::Request { timeout, reply } => match result {
+                Ok(tier) => {
+                    let _ = self.event_tx.send(Arc::new(MessagingEvent::MessageSent(tag, tier)));
+                    if let Some(out_msg) = ack_copy {
+                        self.await_ack(out_msg);
+                    }
+                    self.pending_requests.insert(tag, (node_id, reply));
+                    let mut timeout_tx = self.request_timeout_tx.clone();
+                    self.executor.spawn(async move {
+                        time::sleep(timeout).await;
+                        let _ = timeout_tx.send(tag).await;
+                    });
+                },
+                Err((out_msg, reason)) => {
+                    let _ = self
+                        .event_tx
+                        .send(Arc::new(MessagingEvent::SendMessageFailed(out_msg, reason)));
+                    let _ = reply.send(Err(RpcError::SendFailed(reason)));
+                },
+            },
+            SendCompletion::Retry { attempt } => {
+                match result {
+                    Ok(tier) => {
+                        let _ = self.event_tx.send(Arc::new(MessagingEvent::MessageSent(tag, tier)));
+                        if let Some(out_msg) = ack_copy {
+                            self.await_ack(out_msg);
+                        }
+                    },
+                    Err((out_msg, _reason)) => {
+                        let policy = out_msg.retry_policy.expect("retry-queue entries always carry a policy");
+                        let attempt = attempt + 1;
+                        if attempt >= policy.max_attempts {
+                            let _ = self.event_tx.send(Arc::new(MessagingEvent::SendMessageFailed(
+                                out_msg,
+                                SendFailReason::RetriesExhausted,
+                            )));
+                        } else {
+                            self.retry_queues
+                                .entry(node_id.clone())
+                                .or_insert_with(VecDeque::new)
+                                .push_front(QueuedRetry { out_msg, policy, attempt });
+                        }
+                    },
+                }
+
+                if let Some(next_delay) = self
+                    .retry_queues
+                    .get(&node_id)
+                    .and_then(VecDeque::front)
+                    .map(Self::next_attempt_delay)
+                {
+                    self.schedule_retry(node_id, next_delay);
+                }
+            },
+        }
+    }
+
+    fn construct_envelope(&self, out_msg: &OutboundMessage) -> Envelope {
+        let mut envelope = Envelope::construct_signed(
+            self.node_identity.secret_key(),
+            self.node_identity.public_key(),
+            out_msg.body.clone(),
+            out_msg.flags,
+        )
+        .expect("node identity key is always valid");
+        envelope.request_id = u64::from(out_msg.tag);
+        envelope
+    }
+}
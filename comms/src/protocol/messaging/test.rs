@@ -25,14 +25,20 @@ use super::messaging::{
     MessagingEventReceiver,
     MessagingProtocol,
     MessagingRequest,
+    RpcError,
+    DEFAULT_ACK_TIMEOUT,
     MESSAGING_PROTOCOL,
 };
 use crate::{
-    message::{InboundMessage, MessageExt, MessageFlags, MessageTag, OutboundMessage},
+    message::{InboundMessage, MessageExt, MessageFlags, MessagePriority, MessageTag, OutboundMessage},
     net_address::MultiaddressesWithStats,
     peer_manager::{AsyncPeerManager, NodeId, NodeIdentity, Peer, PeerFeatures, PeerFlags},
     proto::envelope::Envelope,
-    protocol::{messaging::SendFailReason, ProtocolEvent, ProtocolNotification},
+    protocol::{
+        messaging::{RetryPolicy, SendFailReason},
+        ProtocolEvent,
+        ProtocolNotification,
+    },
     test_utils::{
         create_connection_manager_mock,
         create_peer_connection_mock_pair,
@@ -52,12 +58,16 @@ use std::{sync::Arc, time::Duration};
 use tari_crypto::keys::PublicKey;
 use tari_shutdown::Shutdown;
 use tari_test_utils::{collect_stream, unpack_enum};
-use tokio::{runtime::Handle, sync::broadcast, time};
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, oneshot},
+    time,
+};
 use tokio_macros as runtime;
 
 const TEST_MSG1: Bytes = Bytes::from_static(b"TEST_MSG1");
 
-async fn spawn_messaging_protocol() -> (
+async fn spawn_messaging_protocol(ack_timeout: Duration) -> (
     AsyncPeerManager,
     Arc<NodeIdentity>,
     ConnectionManagerMockState,
@@ -90,7 +100,8 @@ async fn spawn_messaging_protocol() -> (
         request_rx,
         events_tx,
         inbound_msg_tx,
-        0,
+        10,
+        ack_timeout,
         shutdown.to_signal(),
     );
     rt_handle.spawn(msg_proto.run());
@@ -110,7 +121,7 @@ async fn spawn_messaging_protocol() -> (
 #[runtime::test_basic]
 async fn new_inbound_substream_handling() {
     let (peer_manager, _, _, mut proto_tx, _, mut inbound_msg_rx, mut events_rx, _shutdown) =
-        spawn_messaging_protocol().await;
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
 
     let expected_node_id = node_id::random();
     let (sk, pk) = CommsPublicKey::random_keypair(&mut OsRng);
@@ -169,7 +180,8 @@ async fn new_inbound_substream_handling() {
 
 #[runtime::test_basic]
 async fn send_message_request() {
-    let (_, node_identity, conn_man_mock, _, mut request_tx, _, _, _shutdown) = spawn_messaging_protocol().await;
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, _, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
 
     let peer_node_id = node_id::random();
 
@@ -196,7 +208,8 @@ async fn send_message_request() {
 
 #[runtime::test_basic]
 async fn send_message_dial_failed() {
-    let (_, _, conn_manager_mock, _, mut request_tx, _, mut event_tx, _shutdown) = spawn_messaging_protocol().await;
+    let (_, _, conn_manager_mock, _, mut request_tx, _, mut event_tx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
 
     let node_id = node_id::random();
     let out_msg = OutboundMessage::new(node_id, MessageFlags::NONE, TEST_MSG1);
@@ -218,7 +231,7 @@ async fn send_message_dial_failed() {
 async fn send_message_substream_bulk_failure() {
     const NUM_MSGS: usize = 10;
     let (_, node_identity, conn_manager_mock, _, mut request_tx, _, mut event_tx, _shutdown) =
-        spawn_messaging_protocol().await;
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
 
     let peer_node_id = node_id::random();
 
@@ -250,7 +263,7 @@ async fn send_message_substream_bulk_failure() {
     }
 
     let event = event_tx.next().await.unwrap().unwrap();
-    unpack_enum!(MessagingEvent::MessageSent(tag) = &*event);
+    unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
     assert_eq!(tag, &expected_out_msg_tags.remove(0));
 
     for _ in 0..NUM_MSGS - 1 {
@@ -264,7 +277,8 @@ async fn send_message_substream_bulk_failure() {
 #[runtime::test_basic]
 async fn many_concurrent_send_message_requests() {
     const NUM_MSGS: usize = 100;
-    let (_, _, conn_man_mock, _, mut request_tx, _, events_rx, _shutdown) = spawn_messaging_protocol().await;
+    let (_, _, conn_man_mock, _, mut request_tx, _, events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
 
     let node_id1 = node_id::random();
     let node_id2 = node_id::random();
@@ -294,7 +308,7 @@ async fn many_concurrent_send_message_requests() {
     assert_eq!(events.len(), NUM_MSGS);
     for event in events {
         let event = event.unwrap();
-        unpack_enum!(MessagingEvent::MessageSent(tag) = &*event);
+        unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
         // Assert that each tag is emitted only once
         let index = msg_tags.iter().position(|t| t == tag).unwrap();
         msg_tags.remove(index);
@@ -302,4 +316,702 @@ async fn many_concurrent_send_message_requests() {
 
     // Got a single call to create a substream
     assert_eq!(peer_conn_mock1.call_count(), 1);
+}
+
+#[runtime::test_basic]
+async fn send_request_receives_reply() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, _, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    let (reply_tx, reply_rx) = oneshot::channel();
+    request_tx
+        .send(MessagingRequest::SendRequest {
+            out_msg,
+            timeout: Duration::from_secs(5),
+            reply: reply_tx,
+        })
+        .await
+        .unwrap();
+
+    // The "remote" peer receives the request and replies on the same substream, setting `in_response_to`
+    let stream = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let msg = framed.next().await.unwrap().unwrap();
+    let request = Envelope::decode(msg).unwrap();
+
+    let mut reply_envelope = Envelope::construct_signed(
+        node_identity.secret_key(),
+        node_identity.public_key(),
+        Bytes::from_static(b"REPLY"),
+        MessageFlags::empty(),
+    )
+    .unwrap();
+    reply_envelope.in_response_to = request.request_id;
+    framed
+        .send(Bytes::copy_from_slice(&reply_envelope.to_encoded_bytes().unwrap()))
+        .await
+        .unwrap();
+
+    let in_msg = time::timeout(Duration::from_secs(5), reply_rx)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(in_msg.body, Bytes::from_static(b"REPLY"));
+}
+
+#[runtime::test_basic]
+async fn send_request_times_out() {
+    let (_, _, conn_man_mock, _, mut request_tx, _, _, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, _peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_id::random(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    let (reply_tx, reply_rx) = oneshot::channel();
+    request_tx
+        .send(MessagingRequest::SendRequest {
+            out_msg,
+            timeout: Duration::from_millis(50),
+            reply: reply_tx,
+        })
+        .await
+        .unwrap();
+
+    let result = time::timeout(Duration::from_secs(5), reply_rx).await.unwrap().unwrap();
+    unpack_enum!(RpcError::Timeout = result.unwrap_err());
+}
+
+#[runtime::test_basic]
+async fn send_request_rejects_retry_policy() {
+    let (_, _, _, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let mut out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    out_msg.retry_policy = Some(RetryPolicy {
+        base_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(100),
+        max_attempts: 5,
+    });
+    let (reply_tx, reply_rx) = oneshot::channel();
+    request_tx
+        .send(MessagingRequest::SendRequest {
+            out_msg,
+            timeout: Duration::from_secs(5),
+            reply: reply_tx,
+        })
+        .await
+        .unwrap();
+
+    let result = time::timeout(Duration::from_secs(5), reply_rx).await.unwrap().unwrap();
+    unpack_enum!(RpcError::SendFailed(reason) = result.unwrap_err());
+    unpack_enum!(SendFailReason::RetryPolicyUnsupported = reason);
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageFailed(_out_msg, reason) = &*event);
+    unpack_enum!(SendFailReason::RetryPolicyUnsupported = reason);
+}
+
+#[runtime::test_basic]
+async fn open_stream_forwards_frames_until_closed() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    let expected_tag = out_msg.tag;
+    let (responses_tx, mut responses_rx) = mpsc::channel(1);
+    request_tx
+        .send(MessagingRequest::OpenStream {
+            out_msg,
+            responses: responses_tx,
+        })
+        .await
+        .unwrap();
+
+    let stream = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    // The stream-open envelope
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
+    assert_eq!(tag, &expected_tag);
+
+    // Each data frame is prefixed with the data marker byte (0)
+    framed.send(Bytes::from_static(b"\x00FRAME1")).await.unwrap();
+    framed.send(Bytes::from_static(b"\x00FRAME2")).await.unwrap();
+    // End-of-stream marker (1)
+    framed.send(Bytes::from_static(b"\x01")).await.unwrap();
+
+    let frame1 = time::timeout(Duration::from_secs(5), responses_rx.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame1, Bytes::from_static(b"FRAME1"));
+    let frame2 = time::timeout(Duration::from_secs(5), responses_rx.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame2, Bytes::from_static(b"FRAME2"));
+    assert!(responses_rx.next().await.is_none());
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::StreamClosed(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+}
+
+#[runtime::test_basic]
+async fn open_stream_emits_stream_closed_on_abrupt_disconnect() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    let expected_tag = out_msg.tag;
+    let (responses_tx, responses_rx) = mpsc::channel(1);
+    request_tx
+        .send(MessagingRequest::OpenStream {
+            out_msg,
+            responses: responses_tx,
+        })
+        .await
+        .unwrap();
+
+    let stream = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    // The stream-open envelope
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
+    assert_eq!(tag, &expected_tag);
+
+    // Drop the remote end of the substream instead of sending an end-of-stream marker.
+    drop(framed);
+    drop(responses_rx);
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::StreamClosed(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+}
+
+#[runtime::test_basic]
+async fn high_priority_message_uses_reserved_substream() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let mut out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    out_msg.priority = MessagePriority::High;
+    request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+
+    let stream = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let msg = framed.next().await.unwrap().unwrap();
+    let msg = Envelope::decode(msg).unwrap();
+    assert_eq!(msg.body, TEST_MSG1);
+
+    let event = events_rx.next().await.unwrap().unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(_tag, priority) = &*event);
+    assert_eq!(priority, &MessagePriority::High);
+}
+
+#[runtime::test_basic]
+async fn high_priority_message_falls_back_to_normal_tier_on_dial_failure() {
+    let (_, _, conn_manager_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let mut out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    out_msg.priority = MessagePriority::High;
+    let expected_tag = out_msg.tag;
+    // No active connection is registered, so both the reserved high-priority tier and the normal-tier fallback
+    // fail to dial.
+    request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+
+    let event = events_rx.next().await.unwrap().unwrap();
+    unpack_enum!(MessagingEvent::SendMessageFailed(out_msg, reason) = &*event);
+    unpack_enum!(SendFailReason::PeerDialFailed = reason);
+    assert_eq!(out_msg.tag, expected_tag);
+
+    // Both tiers independently dial the peer, proving the fallback branch in `dispatch_send` was actually attempted
+    // rather than failing outright after the high-priority tier's first miss.
+    let calls = conn_manager_mock.take_calls().await;
+    assert_eq!(calls.len(), 2);
+    assert!(calls.iter().all(|c| c.starts_with("DialPeer")));
+}
+
+#[runtime::test_basic]
+async fn send_message_with_retry_policy_queues_and_redials() {
+    let (_, _, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let mut out_msg = OutboundMessage::new(peer_node_id.clone(), MessageFlags::NONE, TEST_MSG1);
+    let expected_tag = out_msg.tag;
+    out_msg.retry_policy = Some(RetryPolicy {
+        base_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(100),
+        max_attempts: 5,
+    });
+    // No active connection yet, so the first attempt fails to dial and is queued for retry.
+    request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageQueued(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+
+    // Bring the peer's connection up before the next retry tick fires.
+    let (conn1, _, _, peer_conn_mock2) = create_peer_connection_mock_pair(1, node_id::random(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id, conn1).await;
+
+    let stream = time::timeout(Duration::from_secs(5), peer_conn_mock2.next_incoming_substream())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let msg = framed.next().await.unwrap().unwrap();
+    let msg = Envelope::decode(msg).unwrap();
+    assert_eq!(msg.body, TEST_MSG1);
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
+    assert_eq!(tag, &expected_tag);
+}
+
+#[runtime::test_basic]
+async fn retry_queue_paces_next_attempt_by_front_entrys_own_policy() {
+    let (_, _, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+
+    // A is enqueued first with a long base_delay and exhausts on its very first retry (max_attempts: 1).
+    let mut out_msg_a = OutboundMessage::new(peer_node_id.clone(), MessageFlags::NONE, TEST_MSG1);
+    let tag_a = out_msg_a.tag;
+    out_msg_a.retry_policy = Some(RetryPolicy {
+        base_delay: Duration::from_millis(300),
+        max_delay: Duration::from_millis(300),
+        max_attempts: 1,
+    });
+    request_tx.send(MessagingRequest::SendMessage(out_msg_a)).await.unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageQueued(tag) = &*event);
+    assert_eq!(tag, &tag_a);
+
+    // B is enqueued behind A with a much shorter base_delay.
+    let mut out_msg_b = OutboundMessage::new(peer_node_id.clone(), MessageFlags::NONE, TEST_MSG1);
+    let tag_b = out_msg_b.tag;
+    out_msg_b.retry_policy = Some(RetryPolicy {
+        base_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(100),
+        max_attempts: 5,
+    });
+    request_tx.send(MessagingRequest::SendMessage(out_msg_b)).await.unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageQueued(tag) = &*event);
+    assert_eq!(tag, &tag_b);
+
+    // A's only retry attempt fires at ~300ms and immediately exhausts, leaving B at the front of the queue having
+    // never been tried.
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageFailed(out_msg, reason) = &*event);
+    unpack_enum!(SendFailReason::RetriesExhausted = reason);
+    assert_eq!(out_msg.tag, tag_a);
+
+    // Bring the peer's connection up now. If the next tick were (incorrectly) paced off A's 300ms base_delay, B
+    // would not be retried within this window; pacing off B's own 20ms base_delay succeeds comfortably inside it.
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_id::random(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id, conn1).await;
+
+    let stream = time::timeout(Duration::from_millis(250), peer_conn_mock2.next_incoming_substream())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let msg = framed.next().await.unwrap().unwrap();
+    let msg = Envelope::decode(msg).unwrap();
+    assert_eq!(msg.body, TEST_MSG1);
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
+    assert_eq!(tag, &tag_b);
+}
+
+#[runtime::test_basic]
+async fn send_message_with_retry_policy_redelivers_after_substream_send_failure() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    // Establish the cached outbound substream with an ordinary first message.
+    let out_msg1 = OutboundMessage::new(peer_node_id.clone(), MessageFlags::NONE, TEST_MSG1);
+    request_tx.send(MessagingRequest::SendMessage(out_msg1)).await.unwrap();
+    let _ = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let event = events_rx.next().await.unwrap().unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(_tag, _) = &*event);
+
+    // Close the destination peer's channel out from under the cached substream before the next send, so that
+    // send fails mid-batch with SubstreamSendFailed rather than PeerDialFailed.
+    peer_conn_mock2.disconnect().await;
+
+    let mut out_msg2 = OutboundMessage::new(peer_node_id.clone(), MessageFlags::NONE, TEST_MSG1);
+    let expected_tag = out_msg2.tag;
+    out_msg2.retry_policy = Some(RetryPolicy {
+        base_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(100),
+        max_attempts: 5,
+    });
+    request_tx.send(MessagingRequest::SendMessage(out_msg2)).await.unwrap();
+
+    // The write fails with SubstreamSendFailed, but the attached RetryPolicy means it is queued rather than
+    // dropped with a terminal SendMessageFailed.
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageQueued(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+
+    // Bring a fresh connection up before the next retry tick fires, and the queued message is redelivered.
+    let (conn2, _, _, peer_conn_mock3) =
+        create_peer_connection_mock_pair(1, node_id::random(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id, conn2).await;
+
+    let stream = time::timeout(Duration::from_secs(5), peer_conn_mock3.next_incoming_substream())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let msg = framed.next().await.unwrap().unwrap();
+    let msg = Envelope::decode(msg).unwrap();
+    assert_eq!(msg.body, TEST_MSG1);
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::MessageSent(tag, _) = &*event);
+    assert_eq!(tag, &expected_tag);
+}
+
+#[runtime::test_basic]
+async fn send_message_with_retry_policy_exhausts_after_max_attempts() {
+    let (_, _, _, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    // No active connection is ever registered, so every attempt fails to dial.
+    let mut out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    let expected_tag = out_msg.tag;
+    out_msg.retry_policy = Some(RetryPolicy {
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_attempts: 1,
+    });
+    request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageQueued(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageFailed(out_msg, reason) = &*event);
+    unpack_enum!(SendFailReason::RetriesExhausted = reason);
+    assert_eq!(out_msg.tag, expected_tag);
+}
+
+#[runtime::test_basic]
+async fn send_message_with_retry_policy_fails_when_retry_queue_is_full() {
+    let (_, _, _, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    // A long base_delay keeps every entry queued for the duration of the test rather than being retried.
+    let retry_policy = RetryPolicy {
+        base_delay: Duration::from_secs(30),
+        max_delay: Duration::from_secs(30),
+        max_attempts: 5,
+    };
+
+    // `spawn_messaging_protocol` wires up a per-peer retry capacity of 10; fill it, then overflow it by one.
+    const RETRY_CAPACITY: usize = 10;
+    let mut queued_tags = Vec::with_capacity(RETRY_CAPACITY);
+    for _ in 0..RETRY_CAPACITY {
+        let mut out_msg = OutboundMessage::new(peer_node_id.clone(), MessageFlags::NONE, TEST_MSG1);
+        queued_tags.push(out_msg.tag);
+        out_msg.retry_policy = Some(retry_policy);
+        request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+    }
+    for expected_tag in queued_tags {
+        let event = time::timeout(Duration::from_secs(5), events_rx.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        unpack_enum!(MessagingEvent::SendMessageQueued(tag) = &*event);
+        assert_eq!(tag, &expected_tag);
+    }
+
+    let mut overflow_out_msg = OutboundMessage::new(peer_node_id, MessageFlags::NONE, TEST_MSG1);
+    let expected_tag = overflow_out_msg.tag;
+    overflow_out_msg.retry_policy = Some(retry_policy);
+    request_tx
+        .send(MessagingRequest::SendMessage(overflow_out_msg))
+        .await
+        .unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageFailed(out_msg, reason) = &*event);
+    unpack_enum!(SendFailReason::QueueFull = reason);
+    assert_eq!(out_msg.tag, expected_tag);
+}
+
+#[runtime::test_basic]
+async fn stream_request_received_forwards_body_and_attach_stream_replies() {
+    let (peer_manager, _, _, mut proto_tx, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let expected_node_id = node_id::random();
+    let (sk, pk) = CommsPublicKey::random_keypair(&mut OsRng);
+    peer_manager
+        .add_peer(Peer::new(
+            pk.clone(),
+            expected_node_id.clone(),
+            MultiaddressesWithStats::default(),
+            PeerFlags::empty(),
+            PeerFeatures::COMMUNICATION_CLIENT,
+            &[],
+        ))
+        .await
+        .unwrap();
+
+    // Create connected memory sockets and notify the messaging protocol of a new inbound substream, as a remote
+    // peer opening a stream to us would.
+    let (_, muxer_ours, mut muxer_theirs) = transport::build_multiplexed_connections().await;
+    let stream_ours = muxer_ours.get_yamux_control().open_stream().await.unwrap();
+    proto_tx
+        .send(ProtocolNotification::new(
+            MESSAGING_PROTOCOL.clone(),
+            ProtocolEvent::NewInboundSubstream(Box::new(expected_node_id.clone()), stream_ours),
+        ))
+        .await
+        .unwrap();
+    let stream_theirs = muxer_theirs.incoming_mut().next().await.unwrap().unwrap();
+    let mut framed_theirs = MessagingProtocol::framed(stream_theirs);
+
+    let request_body = Bytes::from_static(b"GIVE ME BLOCKS 100-200");
+    let mut open_envelope = Envelope::construct_signed(&sk, &pk, request_body.clone(), MessageFlags::empty()).unwrap();
+    open_envelope.is_stream_open = true;
+    open_envelope.request_id = 42;
+    framed_theirs
+        .send(Bytes::copy_from_slice(&open_envelope.to_encoded_bytes().unwrap()))
+        .await
+        .unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::StreamRequestReceived(node_id, tag, body) = &*event);
+    assert_eq!(**node_id, expected_node_id);
+    assert_eq!(body, &request_body);
+    let expected_tag = *tag;
+
+    // Attach a reply stream and forward a frame from the application down it.
+    let (mut app_tx, app_rx) = mpsc::channel(1);
+    request_tx
+        .send(MessagingRequest::AttachStream {
+            node_id: expected_node_id,
+            tag: expected_tag,
+            responses: app_rx,
+        })
+        .await
+        .unwrap();
+    app_tx.send(Bytes::from_static(b"REPLY1")).await.unwrap();
+    drop(app_tx);
+
+    let frame1 = time::timeout(Duration::from_secs(5), framed_theirs.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    // Data frames carry a leading data marker byte (0); the stream ends with a lone end marker byte (1).
+    assert_eq!(frame1, Bytes::from_static(b"\x00REPLY1"));
+    let end_marker = time::timeout(Duration::from_secs(5), framed_theirs.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(end_marker, Bytes::from_static(b"\x01"));
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::StreamClosed(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+}
+
+#[runtime::test_basic]
+async fn send_message_with_ack_requested_is_acknowledged() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(DEFAULT_ACK_TIMEOUT).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let out_msg = OutboundMessage::new(peer_node_id, MessageFlags::ACK_REQUESTED, TEST_MSG1);
+    let expected_tag = out_msg.tag;
+    request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+
+    let stream = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let msg = framed.next().await.unwrap().unwrap();
+    let request = Envelope::decode(msg).unwrap();
+
+    // Skip the MessageSent event and reply with an ack, as a well-behaved remote peer would.
+    let _ = events_rx.next().await.unwrap().unwrap();
+    let mut ack = Envelope::construct_signed(
+        node_identity.secret_key(),
+        node_identity.public_key(),
+        Bytes::new(),
+        MessageFlags::empty(),
+    )
+    .unwrap();
+    ack.in_response_to = request.request_id;
+    ack.is_ack = true;
+    framed.send(Bytes::copy_from_slice(&ack.to_encoded_bytes().unwrap())).await.unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::MessageAcknowledged(tag) = &*event);
+    assert_eq!(tag, &expected_tag);
+}
+
+#[runtime::test_basic]
+async fn send_message_with_ack_requested_fails_on_ack_timeout() {
+    let (_, node_identity, conn_man_mock, _, mut request_tx, _, mut events_rx, _shutdown) =
+        spawn_messaging_protocol(Duration::from_millis(50)).await;
+
+    let peer_node_id = node_id::random();
+    let (conn1, _, _, peer_conn_mock2) =
+        create_peer_connection_mock_pair(1, node_identity.node_id().clone(), peer_node_id.clone()).await;
+    conn_man_mock.add_active_connection(peer_node_id.clone(), conn1).await;
+
+    let out_msg = OutboundMessage::new(peer_node_id, MessageFlags::ACK_REQUESTED, TEST_MSG1);
+    let expected_tag = out_msg.tag;
+    request_tx.send(MessagingRequest::SendMessage(out_msg)).await.unwrap();
+
+    let stream = peer_conn_mock2.next_incoming_substream().await.unwrap();
+    let mut framed = MessagingProtocol::framed(stream);
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // Skip the MessageSent event and never send an ack back; the short timeout should fire well within 5 seconds.
+    let _ = events_rx.next().await.unwrap().unwrap();
+
+    let event = time::timeout(Duration::from_secs(5), events_rx.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    unpack_enum!(MessagingEvent::SendMessageFailed(out_msg, reason) = &*event);
+    unpack_enum!(SendFailReason::AckTimeout = reason);
+    assert_eq!(out_msg.tag, expected_tag);
 }
\ No newline at end of file